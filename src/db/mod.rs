@@ -0,0 +1,8 @@
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool as R2D2Pool};
+
+pub mod models;
+pub mod schema;
+pub mod types;
+
+pub type Pool = R2D2Pool<ConnectionManager<PgConnection>>;
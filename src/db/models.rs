@@ -0,0 +1,74 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use failure::Error;
+use serde_json::Value as Json;
+use std::time::Duration;
+
+use super::schema::jobs;
+use super::types::{JobStatus, Timestamp};
+
+#[derive(Debug, Clone, Queryable, Identifiable)]
+#[table_name = "jobs"]
+pub struct JobRecord {
+    pub id: i64,
+    pub kind: String,
+    pub data: Json,
+    pub status: JobStatus,
+    pub attempt: i32,
+    pub max_attempts: i32,
+    pub scheduled_at: Timestamp,
+    pub locked_at: Option<Timestamp>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "jobs"]
+pub struct NewJobRecord {
+    pub kind: &'static str,
+    pub data: Json,
+    pub status: JobStatus,
+    pub max_attempts: i32,
+    pub scheduled_at: Timestamp,
+}
+
+impl JobRecord {
+    /// Marks the job `Dead`; it will not be picked up by the collector again.
+    pub fn kill(&self, conn: &PgConnection) -> Result<(), Error> {
+        use super::schema::jobs::dsl::*;
+
+        diesel::update(jobs.filter(id.eq(self.id)))
+            .set(status.eq(JobStatus::Dead))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Deletes the job row after it completed successfully.
+    pub fn drop(&self, conn: &PgConnection) -> Result<(), Error> {
+        use super::schema::jobs::dsl::*;
+
+        diesel::delete(jobs.filter(id.eq(self.id))).execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Bumps `attempt` and resets the job to `Waiting`, with `scheduled_at` pushed
+    /// `delay` into the future, so the collector picks it back up once that time
+    /// arrives. Callers are expected to have already checked `attempt` against
+    /// `max_attempts` before calling this (see `apply_panic_behavior`).
+    pub fn retry(&self, conn: &PgConnection, delay: Duration) -> Result<(), Error> {
+        use super::schema::jobs::dsl::*;
+
+        let delay = ChronoDuration::from_std(delay).unwrap_or_else(|_| ChronoDuration::zero());
+
+        diesel::update(jobs.filter(id.eq(self.id)))
+            .set((
+                status.eq(JobStatus::Waiting),
+                attempt.eq(attempt + 1),
+                scheduled_at.eq(Utc::now() + delay),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+table! {
+    use diesel::sql_types::{BigInt, Integer, Jsonb, Nullable, SmallInt, Text, Timestamptz};
+
+    jobs (id) {
+        id -> BigInt,
+        kind -> Text,
+        data -> Jsonb,
+        status -> SmallInt,
+        attempt -> Integer,
+        max_attempts -> Integer,
+        scheduled_at -> Timestamptz,
+        locked_at -> Nullable<Timestamptz>,
+    }
+}
@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::SmallInt;
+use std::io::Write;
+
+/// All timestamps on the `jobs` table are stored as `timestamptz`.
+pub type Timestamp = DateTime<Utc>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[sql_type = "SmallInt"]
+pub enum JobStatus {
+    Waiting,
+    Running,
+    Dead,
+}
+
+impl ToSql<SmallInt, Pg> for JobStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        let value: i16 = match self {
+            JobStatus::Waiting => 0,
+            JobStatus::Running => 1,
+            JobStatus::Dead => 2,
+        };
+
+        ToSql::<SmallInt, Pg>::to_sql(&value, out)
+    }
+}
+
+impl FromSql<SmallInt, Pg> for JobStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <i16 as FromSql<SmallInt, Pg>>::from_sql(bytes)? {
+            0 => Ok(JobStatus::Waiting),
+            1 => Ok(JobStatus::Running),
+            2 => Ok(JobStatus::Dead),
+            other => Err(format!("unrecognized job status: {}", other).into()),
+        }
+    }
+}
@@ -1,20 +1,243 @@
+use chrono::{Duration as ChronoDuration, Utc};
 use diesel;
+use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use failure::{format_err, Error};
+use postgres::{Connection as NotifyConnection, TlsMode};
 use serde_derive::{Deserialize, Serialize};
 use serde_json;
 use slog::{slog_debug, slog_error, slog_info, slog_trace};
 use slog_scope::{debug, error, info, trace};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-use turnstile::{self, ExecutionContract, Job, PanicBehavior, Perform, Worker};
+use turnstile::{self, ExecutionContract, Job, PanicBehavior, Perform, RetryPolicy, Worker};
 
-use crate::db::models::JobRecord;
-use crate::db::types::JobStatus;
+use crate::db::models::{JobRecord, NewJobRecord};
+use crate::db::types::{JobStatus, Timestamp};
 use crate::db::Pool;
 
 const BATCH_SIZE: i64 = 10;
-const CHECK_PERIOD: Duration = Duration::from_secs(1); // 1/(1 hz)
+
+// Postgres NOTIFY channel used to wake the collector as soon as a job is enqueued,
+// instead of waiting for the next poll.
+const NOTIFY_CHANNEL: &str = "rustodon_jobs";
+// Fallback poll interval used while waiting on the listening connection, so jobs
+// becoming due via `scheduled_at` are still picked up even without a notification.
+const NOTIFY_FALLBACK_PERIOD: Duration = Duration::from_secs(30);
+
+// Default time the collector gives the thread pool to finish jobs that were already
+// submitted, after `JobRunnerHandle::stop`, before its thread exits anyway.
+// `JobRunnerHandle::drain` replaces it with the caller's own timeout.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a job may sit in `Running` without its heartbeat advancing before the
+// janitor assumes its worker process died and reclaims it.
+const JOB_LEASE_DURATION: ChronoDuration = ChronoDuration::minutes(5);
+
+// How often a still-executing job's `locked_at` heartbeat is refreshed. Must stay
+// well under `JOB_LEASE_DURATION` so a slow-but-alive job isn't reclaimed out from
+// under itself.
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Keeps a job's `locked_at` heartbeat fresh for as long as it's executing, so the
+/// janitor doesn't mistake a slow-but-alive job for an orphaned one and reclaim it
+/// while it's still running (which would let the collector pick it up again and run
+/// two copies concurrently). Dropping the handle stops the heartbeat immediately.
+struct Heartbeat {
+    _stop: mpsc::Sender<()>,
+}
+
+impl Heartbeat {
+    fn start(pool: Pool, job_id: i64) -> Heartbeat {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        thread::Builder::new()
+            .name(format!("job_heartbeat_{}", job_id))
+            .spawn(move || loop {
+                match stop_rx.recv_timeout(HEARTBEAT_PERIOD) {
+                    Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        use crate::db::schema::jobs::dsl::*;
+
+                        let conn = pool.get().expect("couldn't connect to database");
+                        let _ = diesel::update(
+                            jobs.filter(id.eq(job_id)).filter(status.eq(JobStatus::Running)),
+                        )
+                        .set(locked_at.eq(Utc::now()))
+                        .execute(&conn);
+                    }
+                }
+            })
+            .expect("failed to spawn job heartbeat thread");
+
+        Heartbeat { _stop: stop_tx }
+    }
+}
+
+/// Opens a dedicated connection for `LISTEN`, separate from the pool used for
+/// everyday queries, since a listening connection is held open for the lifetime of
+/// the collector rather than checked in and out like a pooled one.
+fn open_notify_listener(database_url: &str) -> NotifyConnection {
+    let listener = NotifyConnection::connect(database_url, TlsMode::None)
+        .expect("couldn't open job queue notification listener");
+
+    listener
+        .execute(&format!("LISTEN {}", NOTIFY_CHANNEL), &[])
+        .expect("couldn't LISTEN on job queue channel");
+
+    listener
+}
+
+/// Blocks until a job-queue notification arrives or `NOTIFY_FALLBACK_PERIOD` elapses,
+/// whichever comes first. This keeps dispatch latency near-zero for freshly enqueued
+/// jobs while still catching scheduled jobs that become due with no new enqueue.
+fn wait_for_wakeup(listener: &NotifyConnection) {
+    listener
+        .notifications()
+        .timeout_iter(NOTIFY_FALLBACK_PERIOD)
+        .next();
+}
+
+/// Reclaims jobs stranded in `Running` by a worker that died before reporting a
+/// result: anything whose `locked_at` heartbeat is older than `JOB_LEASE_DURATION`
+/// is reset to `Waiting` (bumping `attempt`), unless the bumped `attempt` would
+/// reach the job row's own `max_attempts`, in which case it's killed instead. This mirrors
+/// `apply_panic_behavior`'s budget check, since a job whose process reliably dies
+/// (panic-the-process, OOM) never reaches `apply_panic_behavior` on its own.
+fn reclaim_orphaned_jobs(conn: &PgConnection) {
+    use crate::db::schema::jobs::dsl::*;
+
+    let stale = jobs
+        .filter(status.eq(JobStatus::Running))
+        .filter(locked_at.lt(Utc::now() - JOB_LEASE_DURATION))
+        .load::<JobRecord>(conn)
+        .expect("couldn't load stale running jobs");
+
+    for job in &stale {
+        if job.attempt + 1 >= job.max_attempts {
+            info!("Reclaimed job exhausted retry budget, marking dead";
+                "kind" => &job.kind, "id" => job.id, "attempt" => job.attempt);
+            job.kill(conn).expect("failed to kill exhausted reclaimed job");
+        } else {
+            info!("Reclaimed orphaned job past its lease";
+                "kind" => &job.kind, "id" => job.id, "attempt" => job.attempt + 1);
+            diesel::update(jobs.filter(id.eq(job.id)))
+                .set((
+                    status.eq(JobStatus::Waiting),
+                    attempt.eq(job.attempt + 1),
+                    scheduled_at.eq(Utc::now()),
+                ))
+                .execute(conn)
+                .expect("couldn't reclaim orphaned job");
+        }
+    }
+}
+
+/// Computes the delay before the next retry attempt: exponential backoff capped at
+/// `policy.max_delay`, with a small per-job jitter so retries of a batch that failed
+/// together don't all wake up on the same tick.
+fn backoff_delay(policy: &RetryPolicy, attempt: i32, job_id: i64) -> Duration {
+    let exp_ms = policy
+        .base
+        .as_millis()
+        .saturating_mul(1u128 << attempt.max(0).min(30) as u32);
+    let capped_ms = exp_ms.min(policy.max_delay.as_millis()) as u64;
+    let jitter_ms = (job_id as u64).wrapping_mul(2654435761) % 250;
+
+    Duration::from_millis(capped_ms + jitter_ms)
+}
+
+/// Applies a job's declared `PanicBehavior` after a failed execution: retries with
+/// backoff if there's budget left under the job row's own `max_attempts` (captured
+/// at enqueue time from its execution contract, not re-read live here), otherwise
+/// (or for `PanicBehavior::Fail`) kills the job.
+fn apply_panic_behavior(pool: &Pool, job: &JobRecord, behavior: PanicBehavior) {
+    let conn = pool.get().expect("couldn't connect to database");
+
+    match behavior {
+        PanicBehavior::Fail => {
+            job.kill(&conn).expect("failed to kill job");
+        }
+        PanicBehavior::Retry(policy) => {
+            if job.attempt + 1 >= job.max_attempts {
+                info!("Job exhausted retry budget, marking dead";
+                    "kind" => &job.kind, "id" => job.id, "attempt" => job.attempt);
+                job.kill(&conn).expect("failed to kill exhausted job");
+            } else {
+                let delay = backoff_delay(&policy, job.attempt, job.id);
+                info!("Enqueueing job retry";
+                    "kind" => &job.kind, "id" => job.id,
+                    "attempt" => job.attempt + 1, "delay_ms" => delay.as_millis() as u64);
+                job.retry(&conn, delay).expect("failed to reschedule job retry");
+            }
+        }
+    }
+}
+
+/// Enqueues a job of kind `J`, to run as soon as possible, or at `run_at` if given
+/// a future time. Jobs scheduled for later are skipped by the collector until their
+/// time arrives, so this also backs retry backoff.
+/// Takes an existing connection rather than grabbing its own from the pool, so a
+/// caller can enqueue a job inside the same transaction as the domain mutation
+/// that triggers it (e.g. `conn.transaction(|| { create_post(conn)?; enqueue(conn, &job, None) })`).
+/// If that transaction rolls back, the job row (and its `NOTIFY`) never existed;
+/// if it commits, the job is guaranteed present.
+///
+/// This connection-threading was added alongside delayed/scheduled job support so
+/// the caller-supplied `run_at` could share a transaction with the insert; it's
+/// what makes the transactional-enqueue guarantee above hold, not a later addition.
+pub fn enqueue<J>(conn: &PgConnection, job: &J, run_at: Option<Timestamp>) -> Result<(), Error>
+where
+    J: Job + Serialize,
+{
+    use crate::db::schema::jobs::dsl::*;
+
+    // `max_attempts` is captured here, once, from the job kind's current execution
+    // contract, and stored on the row itself; `apply_panic_behavior` and
+    // `reclaim_orphaned_jobs` trust that stored value rather than re-deriving it
+    // live, so a later code deploy changing `J::execution_contract()` can't change
+    // the retry budget out from under jobs already enqueued under the old one.
+    let max_attempts = match J::execution_contract().panic {
+        PanicBehavior::Fail => 1,
+        PanicBehavior::Retry(policy) => policy.max_attempts,
+    };
+
+    diesel::insert_into(jobs)
+        .values(NewJobRecord {
+            kind: J::kind(),
+            data: serde_json::to_value(job)?,
+            status: JobStatus::Waiting,
+            max_attempts,
+            scheduled_at: run_at.unwrap_or_else(|| Utc::now()),
+        })
+        .execute(conn)?;
+
+    // Wake the collector immediately rather than making it wait out its fallback
+    // poll; harmless if nothing is LISTENing yet.
+    diesel::sql_query(format!("NOTIFY {}", NOTIFY_CHANNEL)).execute(conn)?;
+
+    Ok(())
+}
+
+/// Enqueues a job of kind `J`, delayed by `delay` from now.
+pub fn enqueue_after<J>(conn: &PgConnection, job: &J, delay: Duration) -> Result<(), Error>
+where
+    J: Job + Serialize,
+{
+    let delay = ChronoDuration::from_std(delay).unwrap_or_else(|_| ChronoDuration::zero());
+    enqueue(conn, job, Some(Utc::now() + delay))
+}
+
+/// Shared state handed to every job's `perform`, so jobs can act on shared
+/// resources (the database pool, and whatever else lands here later, e.g. a
+/// federation client or settings) instead of reaching for ambient globals.
+#[derive(Clone)]
+pub struct Context {
+    pub pool: Pool,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct TestJob {
@@ -32,12 +255,18 @@ impl Job for TestJob {
     }
 
     fn execution_contract() -> ExecutionContract {
-        ExecutionContract::new()
+        ExecutionContract::new().panic(PanicBehavior::Retry(RetryPolicy {
+            base: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 5,
+        }))
     }
 }
 
-impl Perform for TestJob {
-    fn perform(&self) -> Result<(), Error> {
+impl Perform<Context> for TestJob {
+    fn perform(&self, ctx: &Context) -> Result<(), Error> {
+        let _ = ctx.pool.get().expect("couldn't connect to database");
+
         info!("+++++++ {a} {a} {a} {a} +++++++", a = &self.msg);
 
         // panic!("🅱️anic");
@@ -48,21 +277,81 @@ impl Perform for TestJob {
     }
 }
 
-pub fn init(pool: Pool) {
-    let mut worker = Worker::new();
+/// Returned by `init`; lets callers stop the collector and (for a clean rolling
+/// deploy) wait for in-flight jobs to finish instead of being killed mid-run.
+pub struct JobRunnerHandle {
+    pool: Pool,
+    shutdown: Arc<AtomicBool>,
+    drain_timeout_ms: Arc<AtomicU64>,
+    stopped: mpsc::Receiver<()>,
+}
+
+impl JobRunnerHandle {
+    /// Signals the collector to stop pulling new work, and to drain whatever's
+    /// already in flight using the default `SHUTDOWN_DRAIN_TIMEOUT`. Does not wait
+    /// for it to finish; see `drain` if you need that (and a caller-chosen timeout).
+    ///
+    /// Also sends a `NOTIFY`, the same way `enqueue` does, so a collector parked in
+    /// `wait_for_wakeup` notices the shutdown right away instead of sitting out the
+    /// rest of `NOTIFY_FALLBACK_PERIOD` first.
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+
+        if let Ok(conn) = self.pool.get() {
+            let _ = diesel::sql_query(format!("NOTIFY {}", NOTIFY_CHANNEL)).execute(&conn);
+        }
+    }
+
+    /// Signals the collector to stop, giving the thread pool up to `timeout` to
+    /// finish in-flight jobs (this is the same timeout the collector itself uses
+    /// for `Worker::wait_for_idle`, not just a bound on how long this call blocks),
+    /// then blocks until it reports having drained or `timeout` elapses.
+    pub fn drain(self, timeout: Duration) {
+        self.drain_timeout_ms
+            .store(timeout.as_millis() as u64, Ordering::SeqCst);
+        self.stop();
+        let _ = self.stopped.recv_timeout(timeout);
+    }
+}
+
+pub fn init(pool: Pool, database_url: &str) -> JobRunnerHandle {
+    let context = Context { pool: pool.clone() };
+    let mut worker = Worker::new(context);
 
     worker.register_job::<TestJob>();
 
+    let listener = open_notify_listener(database_url);
+
+    let handle_pool = pool.clone();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_flag = shutdown.clone();
+    let drain_timeout_ms = Arc::new(AtomicU64::new(SHUTDOWN_DRAIN_TIMEOUT.as_millis() as u64));
+    let drain_timeout_ms_handle = drain_timeout_ms.clone();
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+
     thread::Builder::new()
         .name("job_collector".to_string())
         .spawn(move || loop {
+            if shutdown_flag.load(Ordering::SeqCst) {
+                let timeout = Duration::from_millis(drain_timeout_ms_handle.load(Ordering::SeqCst));
+                debug!("job collector draining and shutting down"; "timeout_ms" => timeout.as_millis() as u64);
+                worker.wait_for_idle(timeout);
+                let _ = stopped_tx.send(());
+                break;
+            }
+
             let conn = pool.get().expect("couldn't connect to database");
+
+            // -- reclaim jobs left `Running` by a worker that died mid-execution
+            reclaim_orphaned_jobs(&conn);
+
             // -- pull the top BATCH_SIZE jobs from the queue that are in wait state
             let top_of_queue = {
                 use crate::db::schema::jobs::dsl::*;
                 jobs.filter(status.eq(JobStatus::Waiting))
+                    .filter(scheduled_at.le(Utc::now()))
                     .limit(BATCH_SIZE)
-                    .order(id.asc())
+                    .order((scheduled_at.asc(), id.asc()))
                     .load::<JobRecord>(&conn)
                     .expect("couldn't load from job queue")
             };
@@ -77,15 +366,26 @@ pub fn init(pool: Pool) {
                 use crate::db::schema::jobs::dsl::*;
                 diesel::update(jobs)
                     .filter(id.eq_any(should_run.iter().map(|j| j.id).collect::<Vec<i64>>()))
-                    .set(status.eq(JobStatus::Running))
+                    .set((status.eq(JobStatus::Running), locked_at.eq(Utc::now())))
                     .execute(&conn)
                     .unwrap();
             }
 
             // -- submit jobs which should be run to the thread pool
             let mut failed_to_submit = Vec::new();
+            let mut unsubmitted_on_shutdown = Vec::new();
 
             for job_record in top_of_queue {
+                // A shutdown requested partway through this batch: leave the rest
+                // `Waiting` rather than submitting them, since we're not accepting
+                // new work anymore.
+                if shutdown_flag.load(Ordering::SeqCst) {
+                    unsubmitted_on_shutdown.push(job_record.id);
+                    continue;
+                }
+
+                let heartbeat = Heartbeat::start(pool.clone(), job_record.id);
+
                 let pool = pool.clone();
                 let job_id = job_record.id;
                 let job = job_record.clone();
@@ -94,21 +394,22 @@ pub fn init(pool: Pool) {
                     &job_record.kind.clone(),
                     job_record.data.clone(),
                     move |result, execution_contract| {
+                        // Held until the job has actually finished (however it finished),
+                        // so its heartbeat keeps refreshing for the full duration of execution.
+                        let _heartbeat = heartbeat;
+
                         match result {
                             // If the job encountered an inner error, fail/reschedule it, following the job type's execution policy.
                             Err(turnstile::Error::JobInnerError(inner_error)) => {
                                 error!("Job encountered inner error"; "error" => %inner_error);
+
+                                apply_panic_behavior(&pool, &job, execution_contract.panic);
                             },
                             // If the job panicked, fail/reschedule it, following the job type's execution policy.
                             Err(turnstile::Error::JobPanicked(panic_msg)) => {
                                 error!("Job panicked!"; "panic_message" => %panic_msg);
 
-                                match execution_contract.panic {
-                                    PanicBehavior::Fail => {} // fail the job.
-                                    PanicBehavior::Retry(behavior) => {
-                                        info!("Enqueueing job retry"; "kind" => &job.kind, "id" => &job.id);
-                                    }
-                                }
+                                apply_panic_behavior(&pool, &job, execution_contract.panic);
                             },
 
                             // Immediately terminate the job if we failed to deserialize, since serde is generally deterministic,
@@ -147,7 +448,103 @@ pub fn init(pool: Pool) {
                     .unwrap();
             }
 
-            thread::sleep(CHECK_PERIOD);
+            // -- put back jobs we skipped submitting because of an in-progress shutdown
+            if !unsubmitted_on_shutdown.is_empty() {
+                use crate::db::schema::jobs::dsl::*;
+                diesel::update(jobs)
+                    .filter(id.eq_any(unsubmitted_on_shutdown))
+                    .set(status.eq(JobStatus::Waiting))
+                    .execute(&conn)
+                    .unwrap();
+            }
+
+            wait_for_wakeup(&listener);
         })
         .expect("failed to spawn job_collector thread");
+
+    JobRunnerHandle {
+        pool: handle_pool,
+        shutdown,
+        drain_timeout_ms,
+        stopped: stopped_rx,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            base: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: 5,
+        }
+    }
+
+    #[test]
+    fn backoff_delay_increases_with_attempt() {
+        let policy = policy();
+        let job_id = 1;
+
+        let first = backoff_delay(&policy, 0, job_id);
+        let second = backoff_delay(&policy, 1, job_id);
+        let third = backoff_delay(&policy, 2, job_id);
+
+        assert!(second > first);
+        assert!(third > second);
+    }
+
+    #[test]
+    fn backoff_delay_caps_at_max_delay() {
+        let policy = policy();
+        let job_id = 42;
+
+        let delay = backoff_delay(&policy, 10, job_id);
+
+        assert!(delay >= policy.max_delay);
+        assert!(delay <= policy.max_delay + Duration::from_millis(250));
+    }
+
+    #[test]
+    fn backoff_delay_does_not_overflow_at_large_attempt() {
+        let policy = policy();
+        let job_id = 7;
+
+        let delay = backoff_delay(&policy, i32::max_value(), job_id);
+
+        assert!(delay >= policy.max_delay);
+        assert!(delay <= policy.max_delay + Duration::from_millis(250));
+    }
+
+    // Requires a reachable `DATABASE_URL`; run with the rest of the diesel-backed
+    // suite, not under a plain `cargo test` with no database configured.
+    fn test_conn() -> PgConnection {
+        let database_url =
+            std::env::var("DATABASE_URL").expect("DATABASE_URL must be set to run this test");
+
+        PgConnection::establish(&database_url).expect("couldn't connect to test database")
+    }
+
+    #[test]
+    fn enqueue_rolls_back_with_its_caller_transaction() {
+        use crate::db::schema::jobs::dsl::*;
+
+        let conn = test_conn();
+
+        conn.test_transaction::<_, Error, _>(|| {
+            let job = TestJob { msg: "rollback me".to_string() };
+
+            let result: Result<(), Error> = conn.transaction(|| {
+                enqueue(&conn, &job, None)?;
+                Err(format_err!("force the enclosing transaction to roll back"))
+            });
+            assert!(result.is_err());
+
+            let count: i64 = jobs.filter(kind.eq(TestJob::kind())).count().get_result(&conn)?;
+            assert_eq!(count, 0, "job row should not have survived the rollback");
+
+            Ok(())
+        });
+    }
 }